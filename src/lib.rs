@@ -1,9 +1,26 @@
-use curve25519_dalek::{ristretto::RistrettoPoint, scalar::Scalar};
+// generic-array 0.14 marks `GenericArray` as deprecated in favor of 1.x, but this
+// crate is pinned to the generic-array 0.14 line pulled in by `digest = "0.9"`.
+#![allow(deprecated)]
+
+use core::marker::PhantomData;
+
+use crypto_mac::MacError;
+use curve25519_dalek::{
+    ristretto::{CompressedRistretto, RistrettoPoint},
+    scalar::Scalar,
+};
 use digest::{
     consts::{U32, U64},
     generic_array::GenericArray,
-    Digest, FixedOutput, Reset, Update,
+    Digest, ExtendableOutput, FixedOutput, Reset, Update, XofReader,
 };
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+use subtle::ConstantTimeEq;
+
+/// Domain separation label mixed into every block of `finalize_xof` output,
+/// so that XOF output can never collide with the fixed 32-byte `finalize` output.
+const XOF_DOMAIN_LABEL: &[u8] = b"multiset-hash-xof-v1";
 
 /// RistrettoHash represents a hash function for multi-sets.
 ///
@@ -89,6 +106,42 @@ impl<H: Digest<OutputSize = U64> + Default> RistrettoHash<H> {
         self.end_update(multiplicity);
     }
 
+    /// This function adds many complete objects to the hash, computing their contributions
+    /// in parallel.
+    ///
+    /// The result is identical to calling `add` for each item. Requires the `rayon` feature.
+    #[cfg(feature = "rayon")]
+    pub fn add_all<D: AsRef<[u8]> + Send, I: IntoParallelIterator<Item = (D, u64)>>(
+        &mut self,
+        items: I,
+    ) where
+        I::Iter: Send,
+    {
+        if self.updating {
+            panic!("add_all called before end_update");
+        }
+        let total: RistrettoPoint = items
+            .into_par_iter()
+            .map(|(data, multiplicity)| {
+                let point = RistrettoPoint::from_hash(H::default().chain(data));
+                Scalar::from(multiplicity) * point
+            })
+            .reduce(RistrettoPoint::default, |a, b| a + b);
+        self.acc += total;
+    }
+
+    /// This function removes a complete object from the hash, undoing a previous `add`.
+    ///
+    /// Removing an object that wasn't actually present (or with too high a multiplicity)
+    /// leaves the hash in a state that doesn't correspond to any real multi-set; avoid this.
+    pub fn remove(&mut self, data: impl AsRef<[u8]>, multiplicity: u64) {
+        if self.updating {
+            panic!("remove called before end_update");
+        }
+        self.hash.update(data);
+        self.end_update_remove(multiplicity);
+    }
+
     /// This function should be called to mark the end of an object provided with `update`.
     ///
     /// This must always be called after calls to `update`, otherwise panics will happen
@@ -99,10 +152,63 @@ impl<H: Digest<OutputSize = U64> + Default> RistrettoHash<H> {
     pub fn end_update(&mut self, multiplicity: u64) {
         self.updating = false;
 
-        let old = std::mem::replace(&mut self.hash, H::default());
+        let old = std::mem::take(&mut self.hash);
         let h_point = RistrettoPoint::from_hash(old);
         self.acc += Scalar::from(multiplicity) * h_point;
     }
+
+    /// This function should be called to mark the end of an object provided with `update`,
+    /// when that object is being removed rather than added.
+    ///
+    /// This behaves exactly like `end_update`, except the object's contribution
+    /// is subtracted from the accumulator instead of being added to it.
+    pub fn end_update_remove(&mut self, multiplicity: u64) {
+        self.updating = false;
+
+        let old = std::mem::take(&mut self.hash);
+        let h_point = RistrettoPoint::from_hash(old);
+        self.acc -= Scalar::from(multiplicity) * h_point;
+    }
+
+    /// This function combines another multi-set hash into this one.
+    ///
+    /// This lets a large multi-set be sharded, hashed independently, and folded back
+    /// together. Both hashes must not be in the middle of an `update`, otherwise this
+    /// function panics.
+    pub fn combine(&mut self, other: &Self) {
+        if self.updating || other.updating {
+            panic!("combine called with an unfinished update");
+        }
+        self.acc += other.acc;
+    }
+
+    /// This function exports the current accumulator as a checkpoint.
+    ///
+    /// The returned bytes are the compressed Ristretto encoding of the
+    /// accumulator, and can be persisted and later restored with
+    /// `from_state_bytes` to resume hashing a multi-set without having
+    /// to replay every object added so far.
+    ///
+    /// This is only valid to call when not in the middle of an `update`;
+    /// a partial block has no well-defined contribution to export.
+    pub fn to_state_bytes(&self) -> [u8; 32] {
+        if self.updating {
+            panic!("to_state_bytes called before end_update");
+        }
+        self.acc.compress().to_bytes()
+    }
+
+    /// This function restores a hash from a checkpoint created by `to_state_bytes`.
+    ///
+    /// Returns `None` if `bytes` is not a valid compressed Ristretto point.
+    pub fn from_state_bytes(bytes: [u8; 32]) -> Option<Self> {
+        let acc = CompressedRistretto(bytes).decompress()?;
+        Some(RistrettoHash {
+            hash: H::default(),
+            updating: false,
+            acc,
+        })
+    }
 }
 
 impl<H: Reset> FixedOutput for RistrettoHash<H> {
@@ -124,6 +230,40 @@ impl<H: Reset> FixedOutput for RistrettoHash<H> {
     }
 }
 
+impl<H: Reset> RistrettoHash<H> {
+    /// This function checks that the hash finalizes to `expected`, consuming `self`.
+    ///
+    /// The comparison is done in constant time, using `subtle::ConstantTimeEq`,
+    /// since multi-set hashes are often used as integrity tags over database
+    /// or ledger state, where a naive `==` comparison would leak timing
+    /// information about how many leading bytes of the tag matched.
+    pub fn verify(self, expected: &[u8; 32]) -> Result<(), MacError> {
+        if self.updating {
+            panic!("end_update not called before verifying");
+        }
+        let out = self.acc.compress().to_bytes();
+        if out.ct_eq(expected).into() {
+            Ok(())
+        } else {
+            Err(MacError)
+        }
+    }
+
+    /// This function behaves like `verify`, but resets the hash instead of consuming it.
+    pub fn verify_reset(&mut self, expected: &[u8; 32]) -> Result<(), MacError> {
+        if self.updating {
+            panic!("end_update not called before verifying");
+        }
+        let out = self.acc.compress().to_bytes();
+        self.reset();
+        if out.ct_eq(expected).into() {
+            Ok(())
+        } else {
+            Err(MacError)
+        }
+    }
+}
+
 impl<H: Reset> Reset for RistrettoHash<H> {
     fn reset(&mut self) {
         self.hash.reset();
@@ -132,6 +272,78 @@ impl<H: Reset> Reset for RistrettoHash<H> {
     }
 }
 
+impl<H: Digest<OutputSize = U64> + Default + Reset> ExtendableOutput for RistrettoHash<H> {
+    type Reader = RistrettoHashXofReader<H>;
+
+    fn finalize_xof(self) -> Self::Reader {
+        if self.updating {
+            panic!("end_update not called before finalizing");
+        }
+        RistrettoHashXofReader::new(self.acc.compress().to_bytes())
+    }
+
+    fn finalize_xof_reset(&mut self) -> Self::Reader {
+        if self.updating {
+            panic!("end_update not called before finalizing");
+        }
+        let acc_bytes = self.acc.compress().to_bytes();
+        self.reset();
+        RistrettoHashXofReader::new(acc_bytes)
+    }
+}
+
+/// A reader producing an extendable-output stream from a finalized `RistrettoHash`.
+///
+/// This is returned by `RistrettoHash::finalize_xof`, and yields an arbitrary
+/// number of bytes, derived by hashing successive 64-byte blocks of the
+/// domain-separated accumulator together with a little-endian block counter.
+pub struct RistrettoHashXofReader<H> {
+    acc_bytes: [u8; 32],
+    counter: u64,
+    block: GenericArray<u8, U64>,
+    consumed: usize,
+    _hash: PhantomData<H>,
+}
+
+impl<H: Digest<OutputSize = U64> + Default> RistrettoHashXofReader<H> {
+    fn new(acc_bytes: [u8; 32]) -> Self {
+        RistrettoHashXofReader {
+            acc_bytes,
+            counter: 0,
+            block: GenericArray::default(),
+            // Forces the first call to `read` to generate the initial block.
+            consumed: 64,
+            _hash: PhantomData,
+        }
+    }
+
+    fn next_block(&mut self) {
+        let mut hash = H::default();
+        hash.update(XOF_DOMAIN_LABEL);
+        hash.update(self.acc_bytes);
+        hash.update(self.counter.to_le_bytes());
+        self.block = hash.finalize();
+        self.counter += 1;
+        self.consumed = 0;
+    }
+}
+
+impl<H: Digest<OutputSize = U64> + Default> XofReader for RistrettoHashXofReader<H> {
+    fn read(&mut self, buffer: &mut [u8]) {
+        let mut filled = 0;
+        while filled < buffer.len() {
+            if self.consumed == self.block.len() {
+                self.next_block();
+            }
+            let available = &self.block[self.consumed..];
+            let to_copy = available.len().min(buffer.len() - filled);
+            buffer[filled..filled + to_copy].copy_from_slice(&available[..to_copy]);
+            self.consumed += to_copy;
+            filled += to_copy;
+        }
+    }
+}
+
 impl<H: Update> Update for RistrettoHash<H> {
     /// update hashes in part of an object.
     ///
@@ -147,12 +359,33 @@ impl<H: Update> Update for RistrettoHash<H> {
     }
 }
 
+#[cfg(feature = "serde")]
+impl<H: Digest<OutputSize = U64> + Default> serde::Serialize for RistrettoHash<H> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.updating {
+            return Err(serde::ser::Error::custom(
+                "cannot serialize a RistrettoHash with an unfinished update",
+            ));
+        }
+        serializer.serialize_bytes(&self.to_state_bytes())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, H: Digest<OutputSize = U64> + Default> serde::Deserialize<'de> for RistrettoHash<H> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bytes: [u8; 32] = serde::Deserialize::deserialize(deserializer)?;
+        RistrettoHash::from_state_bytes(bytes)
+            .ok_or_else(|| serde::de::Error::custom("invalid Ristretto point encoding"))
+    }
+}
+
 #[cfg(test)]
 mod test {
     use sha2::Sha512;
 
     use super::RistrettoHash;
-    use digest::Digest;
+    use digest::{Digest, ExtendableOutput, XofReader};
 
     #[test]
     fn test_add_with_multiplicity() {
@@ -221,4 +454,168 @@ mod test {
         hash.update("some data");
         hash.finalize();
     }
+
+    #[test]
+    fn test_combine_matches_single_add() {
+        let data_a = b"test data A";
+        let data_b = b"test data B";
+
+        let mut combined = RistrettoHash::<Sha512>::default();
+        combined.add(data_a, 2);
+        combined.add(data_b, 3);
+
+        let mut shard1 = RistrettoHash::<Sha512>::default();
+        shard1.add(data_a, 2);
+        let mut shard2 = RistrettoHash::<Sha512>::default();
+        shard2.add(data_b, 3);
+        shard1.combine(&shard2);
+
+        assert_eq!(combined.finalize(), shard1.finalize());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_combine_before_end_update_panics() {
+        let mut hash1 = RistrettoHash::<Sha512>::default();
+        let mut hash2 = hash1.clone();
+        hash2.update("some data");
+        hash1.combine(&hash2);
+    }
+
+    #[test]
+    fn test_state_bytes_round_trip() {
+        let mut hash = RistrettoHash::<Sha512>::default();
+        hash.add(b"cat", 2);
+        hash.add(b"dog", 3);
+
+        let bytes = hash.to_state_bytes();
+        let restored = RistrettoHash::<Sha512>::from_state_bytes(bytes).unwrap();
+
+        assert_eq!(hash.finalize(), restored.finalize());
+    }
+
+    #[test]
+    fn test_from_state_bytes_rejects_invalid_encoding() {
+        assert!(RistrettoHash::<Sha512>::from_state_bytes([0xffu8; 32]).is_none());
+    }
+
+    #[test]
+    fn test_verify_accepts_matching_digest() {
+        let mut hash = RistrettoHash::<Sha512>::default();
+        hash.add(b"cat", 2);
+        let expected = hash.to_state_bytes();
+
+        assert!(hash.verify(&expected).is_ok());
+    }
+
+    #[test]
+    fn test_verify_rejects_wrong_digest() {
+        let mut hash = RistrettoHash::<Sha512>::default();
+        hash.add(b"cat", 2);
+
+        assert!(hash.verify(&[0u8; 32]).is_err());
+    }
+
+    #[test]
+    fn test_verify_reset_resets_state() {
+        let mut hash = RistrettoHash::<Sha512>::default();
+        hash.add(b"cat", 2);
+        let expected = hash.to_state_bytes();
+
+        assert!(hash.verify_reset(&expected).is_ok());
+
+        let fresh = RistrettoHash::<Sha512>::default();
+        assert_eq!(hash.finalize(), fresh.finalize());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_verify_before_end_update_panics() {
+        let mut hash = RistrettoHash::<Sha512>::default();
+        hash.update("some data");
+        let _ = hash.verify(&[0u8; 32]);
+    }
+
+    #[test]
+    fn test_remove_returns_to_identity() {
+        let mut hash = RistrettoHash::<Sha512>::default();
+        hash.add(b"cat", 2);
+        hash.add(b"dog", 5);
+
+        hash.remove(b"cat", 2);
+        hash.remove(b"dog", 5);
+
+        let empty = RistrettoHash::<Sha512>::default();
+        assert_eq!(hash.finalize(), empty.finalize());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_remove_before_end_update_panics() {
+        let mut hash = RistrettoHash::<Sha512>::default();
+        hash.update("some data");
+        hash.remove("more data", 1);
+    }
+
+    #[test]
+    #[cfg(feature = "rayon")]
+    fn test_add_all_matches_sequential_add() {
+        let items = vec![
+            (b"cat".to_vec(), 2u64),
+            (b"dog".to_vec(), 3),
+            (b"bird".to_vec(), 1),
+        ];
+
+        let mut sequential = RistrettoHash::<Sha512>::default();
+        for (data, multiplicity) in &items {
+            sequential.add(data, *multiplicity);
+        }
+
+        let mut parallel = RistrettoHash::<Sha512>::default();
+        parallel.add_all(items);
+
+        assert_eq!(sequential.finalize(), parallel.finalize());
+    }
+
+    #[test]
+    #[should_panic]
+    #[cfg(feature = "rayon")]
+    fn test_add_all_before_end_update_panics() {
+        let mut hash = RistrettoHash::<Sha512>::default();
+        hash.update("some data");
+        hash.add_all(vec![(b"more data".to_vec(), 1u64)]);
+    }
+
+    #[test]
+    fn test_finalize_xof_matches_chunked_read() {
+        let mut hash1 = RistrettoHash::<Sha512>::default();
+        hash1.add(b"cat", 2);
+        hash1.add(b"dog", 3);
+        let hash2 = hash1.clone();
+
+        let mut one_shot = [0u8; 1000];
+        hash1.finalize_xof().read(&mut one_shot);
+
+        let mut chunked = [0u8; 1000];
+        let mut reader = hash2.finalize_xof();
+        for chunk in chunked.chunks_mut(7) {
+            reader.read(chunk);
+        }
+
+        assert_eq!(&one_shot[..], &chunked[..]);
+    }
+
+    #[test]
+    fn test_finalize_xof_differs_from_finalize() {
+        let mut hash1 = RistrettoHash::<Sha512>::default();
+        hash1.add(b"cat", 2);
+        let hash2 = hash1.clone();
+
+        let fixed = hash1.finalize();
+
+        let mut xof_prefix = [0u8; 32];
+        hash2.finalize_xof().read(&mut xof_prefix);
+
+        assert_ne!(&fixed[..], &xof_prefix[..]);
+    }
 }